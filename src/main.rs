@@ -1,14 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Range;
 use std::error::Error as StdError;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::{cmp, process};
 use structopt::StructOpt;
-use sv_parser::{parse_sv, SyntaxTree, unwrap_node, Locate, RefNode, Define, DefineText};
+use sv_parser::{parse_sv, preprocess, SyntaxTree, unwrap_node, Locate, RefNode, Define, DefineText, PreprocessedText};
 use sv_parser_error;
 use sv_parser_syntaxtree::*;
 use enquote;
+use serde::Serialize;
 
 #[derive(StructOpt)]
 struct Opt {
@@ -44,15 +46,105 @@ struct Opt {
 
     /// Allow incomplete
     #[structopt(long = "allow_incomplete")]
-    pub allow_incomplete: bool
+    pub allow_incomplete: bool,
+
+    /// Stop before parsing and print the fully preprocessed source plus an origin map
+    #[structopt(long = "preprocess")]
+    pub preprocess: bool,
+
+    /// Build a cross-file module instantiation hierarchy and report top/unresolved modules
+    #[structopt(long = "hierarchy")]
+    pub hierarchy: bool,
+
+    /// With --hierarchy, dump the instantiation graph in Graphviz DOT format instead of a tree
+    #[structopt(long = "dot")]
+    pub dot: bool,
+
+    /// Structured output format for the default (non --full-tree) run
+    #[structopt(long = "format", default_value = "yaml", possible_values = &["yaml", "json"])]
+    pub format: String,
+
+    /// Write a Make-style depfile listing every file touched while preprocessing each input
+    #[structopt(long = "depfile")]
+    pub depfile: Option<PathBuf>
 }
 
 fn main() {
     let opt = Opt::from_args();
-    let exit_code = run_opt(&opt);
+    let exit_code = if opt.preprocess {
+        run_preprocess(&opt)
+    } else if opt.hierarchy {
+        run_hierarchy(&opt)
+    } else {
+        run_opt(&opt)
+    };
     process::exit(exit_code);
 }
 
+// The top-level structured document produced by run_opt's default (non
+// --full-tree) mode.
+#[derive(Serialize)]
+struct RunOutput {
+	files: Vec<FileResult>
+}
+
+// One input file's outcome: either its definitions (and, if requested, the
+// macro table after processing it) or the error that stopped parsing.
+#[derive(Serialize)]
+struct FileResult {
+	file_name: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	defs: Option<Vec<ModuleDef>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	macro_defs: Option<Vec<String>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<ParseErrorInfo>
+}
+
+#[derive(Serialize)]
+struct ParseErrorInfo {
+	message: String,
+	location: Option<Location>
+}
+
+fn collect_macro_defs(
+	defines: &HashMap<String, Option<Define>>
+) -> Vec<String> {
+	defines.values()
+		.filter_map(|define| define.as_ref().map(|x| format!("{:?}", x)))
+		.collect()
+}
+
+// Translate a parse_sv failure into a ParseErrorInfo: a human-readable
+// message plus, when available, the original-source location the failure
+// offset maps back to. sv-parser already resolves Error::Parse's offset
+// through the preprocessor's origin table before raising it, so origin_path
+// and origin_pos are already the real file and the real offset in it.
+fn describe_parse_error(
+	path: &PathBuf,
+	err: sv_parser_error::Error,
+	origin_lines: &mut HashMap<PathBuf, LineIndex>
+) -> ParseErrorInfo {
+	match err {
+		sv_parser_error::Error::Parse(Some((origin_path, origin_pos))) => {
+			let (line, col) = line_col_at(origin_lines, &origin_path, origin_pos);
+			ParseErrorInfo {
+				message: format!("parse failed: {:?}", path),
+				location: Some(Location { file: origin_path.to_string_lossy().into_owned(), line, col })
+			}
+		}
+		x => {
+			let mut message = format!("parse failed: {:?} ({})", path, x);
+			let mut cause = x.source();
+			while let Some(e) = cause {
+				message.push_str(&format!(" | caused by: {}", e));
+				cause = e.source();
+			}
+			ParseErrorInfo { message, location: None }
+		}
+	}
+}
+
 fn run_opt(
 	opt: &Opt
 ) -> i32 {
@@ -71,22 +163,161 @@ fn run_opt(
         let define = Define::new(ident.clone(), vec![], text);
         defines.insert(ident, Some(define));
 	}
-    
+
     // flag to determine parsing status
     let mut exit_code = 0;
-    
-    // parse files
-    println!("files:");
+
+    // cache of line-start offsets per origin file, so repeated lookups
+    // don't re-read the same file from disk
+    let mut origin_lines: HashMap<PathBuf, LineIndex> = HashMap::new();
+
+    // --depfile entries accumulated as files are processed below, one
+    // (target, prereqs) pair per top-level input
+    let mut depfile_entries: Vec<(PathBuf, BTreeSet<PathBuf>)> = Vec::new();
+
+    // --full-tree dumps the raw syntax tree, which doesn't fit the
+    // definitions/instances schema below, so it keeps its own textual format
+    if opt.full_tree {
+        println!("files:");
+        for path in &opt.files {
+            if opt.depfile.is_some() {
+                depfile_entries.push((path.clone(), collect_include_deps(path, &defines, &opt.includes, opt.ignore_include, opt.allow_incomplete)));
+            }
+            match parse_sv(&path, &defines, &opt.includes, opt.ignore_include, opt.allow_incomplete) {
+                Ok((syntax_tree, new_defines)) => {
+					println!("  - file_name: {}", escape_str(path.to_str().unwrap()));
+					println!("    syntax_tree:");
+					print_full_tree(&syntax_tree, opt.include_whitespace);
+					if !opt.separate {
+						defines = new_defines;
+					}
+					if opt.show_macro_defs {
+						println!("    macro_defs:");
+						show_macro_defs(&defines);
+					}
+                }
+                Err(x) => {
+                    match x {
+                        sv_parser_error::Error::Parse(Some((origin_path, origin_pos))) => {
+                            eprintln!("parse failed: {:?}", path);
+                            print_parse_error(&origin_path, origin_pos);
+                        }
+                        x => {
+                            eprintln!("parse failed: {:?} ({})", path, x);
+                            let mut err = x.source();
+                            while let Some(x) = err {
+                                eprintln!("  Caused by {}", x);
+                                err = x.source();
+                            }
+                        }
+                    }
+					exit_code = 1;
+                }
+            }
+        }
+        if let Some(depfile) = &opt.depfile {
+            write_depfile(depfile, &depfile_entries);
+        }
+        return exit_code;
+    }
+
+    // parse files, building one structured FileResult per file
+    let mut files: Vec<FileResult> = Vec::new();
     for path in &opt.files {
+        if opt.depfile.is_some() {
+            depfile_entries.push((path.clone(), collect_include_deps(path, &defines, &opt.includes, opt.ignore_include, opt.allow_incomplete)));
+        }
         match parse_sv(&path, &defines, &opt.includes, opt.ignore_include, opt.allow_incomplete) {
             Ok((syntax_tree, new_defines)) => {
-				println!("  - file_name: {}", escape_str(path.to_str().unwrap()));
-				if !opt.full_tree {
-					println!("    defs:");
-					analyze_defs(&syntax_tree);
+				let defs = collect_defs(&syntax_tree, &mut origin_lines);
+				// update the preprocessor state if desired
+				if !opt.separate {
+					defines = new_defines;
+				}
+				let macro_defs = if opt.show_macro_defs {
+					Some(collect_macro_defs(&defines))
 				} else {
-					println!("    syntax_tree:");
-					print_full_tree(&syntax_tree, opt.include_whitespace);
+					None
+				};
+				files.push(FileResult {
+					file_name: path.to_string_lossy().into_owned(),
+					defs: Some(defs),
+					macro_defs,
+					error: None
+				});
+            }
+            Err(x) => {
+				let error = describe_parse_error(path, x, &mut origin_lines);
+				files.push(FileResult {
+					file_name: path.to_string_lossy().into_owned(),
+					defs: None,
+					macro_defs: None,
+					error: Some(error)
+				});
+				exit_code = 1;
+            }
+        }
+    }
+
+    if let Some(depfile) = &opt.depfile {
+        write_depfile(depfile, &depfile_entries);
+    }
+
+    let output = RunOutput { files };
+    let rendered = match opt.format.as_str() {
+        "json" => serde_json::to_string_pretty(&output).unwrap(),
+        _ => serde_yaml::to_string(&output).unwrap()
+    };
+    print!("{}", rendered);
+
+    // return exit code
+    exit_code
+}
+
+fn run_preprocess(
+	opt: &Opt
+) -> i32 {
+
+    // read in define variables
+    let mut defines = HashMap::new();
+    for define in &opt.defines {
+		let mut define = define.splitn(2, '=');
+        let ident = String::from(define.next().unwrap());
+        let text = if let Some(x) = define.next() {
+            let x = enquote::unescape(x, None).unwrap();
+            Some(DefineText::new(x, None))
+        } else {
+            None
+        };
+        let define = Define::new(ident.clone(), vec![], text);
+        defines.insert(ident, Some(define));
+	}
+
+    // flag to determine preprocessing status
+    let mut exit_code = 0;
+
+    // preprocess files
+    println!("files:");
+    for path in &opt.files {
+        match preprocess(&path, &defines, &opt.includes, opt.ignore_include, opt.allow_incomplete) {
+            Ok((preprocessed, new_defines)) => {
+				println!("  - file_name: {}", escape_str(path.to_str().unwrap()));
+				println!("    text: {}", escape_str(preprocessed.text()));
+				println!("    origin_map:");
+				for (range, origin) in collect_origin_spans(&preprocessed) {
+					println!("      - output_start: {}", range.start);
+					println!("        output_end: {}", range.end);
+					match origin {
+						Some((origin_path, source_start)) => {
+							let source_end = source_start + (range.end - range.start);
+							println!("        source_file: {}", escape_str(&origin_path.to_string_lossy()));
+							println!("        source_start: {}", source_start);
+							println!("        source_end: {}", source_end);
+						}
+						None => {
+							println!("        source_file: ~");
+						}
+					}
 				}
 				// update the preprocessor state if desired
 				if !opt.separate {
@@ -98,11 +329,234 @@ fn run_opt(
 					show_macro_defs(&defines);
 				}
             }
+            Err(x) => {
+                eprintln!("preprocess failed: {:?} ({})", path, x);
+				exit_code = 1;
+            }
+        }
+    }
+
+    // return exit code
+    exit_code
+}
+
+// What kind of thing a ModuleDef describes.
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DefKind {
+	Module,
+	Package,
+	Interface
+}
+
+// A file/line/col triple pointing into the original (pre-preprocessing) source.
+#[derive(Serialize)]
+struct Location {
+	file: String,
+	line: usize,
+	col: usize
+}
+
+impl Location {
+	fn from_origin(origin: Option<(PathBuf, usize, usize)>) -> Option<Location> {
+		let (file, line, col) = origin?;
+		Some(Location { file: file.to_string_lossy().into_owned(), line, col })
+	}
+}
+
+// A single module instantiation: which module was instantiated, under what
+// instance name, and where each of those names appears in the source.
+#[derive(Serialize)]
+struct Inst {
+	mod_name: String,
+	mod_location: Option<Location>,
+	inst_name: String,
+	inst_location: Option<Location>
+}
+
+// A package or class-scope name referenced from within a definition, along
+// with where that reference appears in the source.
+#[derive(Serialize)]
+struct PkgRef {
+	name: String,
+	location: Option<Location>
+}
+
+// One module/interface/package definition found while scanning a file, along
+// with every module it instantiates (used to build the cross-file hierarchy
+// in run_hierarchy and the structured output in run_opt).
+#[derive(Serialize)]
+struct ModuleDef {
+	name: String,
+	kind: DefKind,
+	location: Option<Location>,
+	insts: Vec<Inst>,
+	pkg_refs: Vec<PkgRef>
+}
+
+// Walks the syntax tree accumulating structured records instead of printing,
+// so callers can reason about the design as a whole rather than one file at
+// a time. Instances and package references are attributed to the most
+// recently seen definition, mirroring the document-order nesting a SystemVerilog
+// file implies.
+fn collect_defs(
+	syntax_tree: &SyntaxTree,
+	origin_lines: &mut HashMap<PathBuf, LineIndex>
+) -> Vec<ModuleDef> {
+	let mut defs: Vec<ModuleDef> = Vec::new();
+
+	for node in syntax_tree {
+		match node {
+			RefNode::ModuleDeclarationNonansi(x) => {
+				let id = match unwrap_node!(x, ModuleIdentifier) { None => continue, Some(x) => x };
+				let id = match get_identifier(id) { None => continue, Some(x) => x };
+				let name = match syntax_tree.get_str(&id) { None => continue, Some(x) => x };
+				let location = Location::from_origin(locate_origin(syntax_tree, &id, origin_lines));
+				defs.push(ModuleDef { name: name.to_string(), kind: DefKind::Module, location, insts: vec![], pkg_refs: vec![] });
+			}
+			RefNode::ModuleDeclarationAnsi(x) => {
+				let id = match unwrap_node!(x, ModuleIdentifier) { None => continue, Some(x) => x };
+				let id = match get_identifier(id) { None => continue, Some(x) => x };
+				let name = match syntax_tree.get_str(&id) { None => continue, Some(x) => x };
+				let location = Location::from_origin(locate_origin(syntax_tree, &id, origin_lines));
+				defs.push(ModuleDef { name: name.to_string(), kind: DefKind::Module, location, insts: vec![], pkg_refs: vec![] });
+			}
+			RefNode::PackageDeclaration(x) => {
+				let id = match unwrap_node!(x, PackageIdentifier) { None => continue, Some(x) => x };
+				let id = match get_identifier(id) { None => continue, Some(x) => x };
+				let name = match syntax_tree.get_str(&id) { None => continue, Some(x) => x };
+				let location = Location::from_origin(locate_origin(syntax_tree, &id, origin_lines));
+				defs.push(ModuleDef { name: name.to_string(), kind: DefKind::Package, location, insts: vec![], pkg_refs: vec![] });
+			}
+			RefNode::InterfaceDeclaration(x) => {
+				let id = match unwrap_node!(x, InterfaceIdentifier) { None => continue, Some(x) => x };
+				let id = match get_identifier(id) { None => continue, Some(x) => x };
+				let name = match syntax_tree.get_str(&id) { None => continue, Some(x) => x };
+				let location = Location::from_origin(locate_origin(syntax_tree, &id, origin_lines));
+				defs.push(ModuleDef { name: name.to_string(), kind: DefKind::Interface, location, insts: vec![], pkg_refs: vec![] });
+			}
+			RefNode::ModuleInstantiation(x) => {
+				let mod_id = match unwrap_node!(x, ModuleIdentifier) { None => continue, Some(x) => x };
+				let mod_id = match get_identifier(mod_id) { None => continue, Some(x) => x };
+				let mod_name = match syntax_tree.get_str(&mod_id) { None => continue, Some(x) => x };
+				let mod_location = Location::from_origin(locate_origin(syntax_tree, &mod_id, origin_lines));
+
+				let inst_id = match unwrap_node!(x, InstanceIdentifier) { None => continue, Some(x) => x };
+				let inst_id = match get_identifier(inst_id) { None => continue, Some(x) => x };
+				let inst_name = match syntax_tree.get_str(&inst_id) { None => continue, Some(x) => x };
+				let inst_location = Location::from_origin(locate_origin(syntax_tree, &inst_id, origin_lines));
+
+				if let Some(last) = defs.last_mut() {
+					last.insts.push(Inst {
+						mod_name: mod_name.to_string(),
+						mod_location,
+						inst_name: inst_name.to_string(),
+						inst_location
+					});
+				}
+			}
+			RefNode::PackageImportItem(x) => {
+				let id = match unwrap_node!(x, PackageIdentifier) { None => continue, Some(x) => x };
+				let id = match get_identifier(id) { None => continue, Some(x) => x };
+				let name = match syntax_tree.get_str(&id) { None => continue, Some(x) => x };
+				let location = Location::from_origin(locate_origin(syntax_tree, &id, origin_lines));
+				if let Some(last) = defs.last_mut() {
+					last.pkg_refs.push(PkgRef { name: name.to_string(), location });
+				}
+			}
+			RefNode::ImplicitClassHandleOrClassScope(x) => {
+				let id = match unwrap_node!(x, ClassIdentifier) { None => continue, Some(x) => x };
+				let id = match get_identifier(id) { None => continue, Some(x) => x };
+				let name = match syntax_tree.get_str(&id) { None => continue, Some(x) => x };
+				let location = Location::from_origin(locate_origin(syntax_tree, &id, origin_lines));
+				if let Some(last) = defs.last_mut() {
+					last.pkg_refs.push(PkgRef { name: name.to_string(), location });
+				}
+			}
+			RefNode::ImplicitClassHandleOrClassScopeOrPackageScope(x) => {
+				let id = match unwrap_node!(x, ClassIdentifier) { None => continue, Some(x) => x };
+				let id = match get_identifier(id) { None => continue, Some(x) => x };
+				let name = match syntax_tree.get_str(&id) { None => continue, Some(x) => x };
+				let location = Location::from_origin(locate_origin(syntax_tree, &id, origin_lines));
+				if let Some(last) = defs.last_mut() {
+					last.pkg_refs.push(PkgRef { name: name.to_string(), location });
+				}
+			}
+			_ => (),
+		}
+	}
+
+	defs
+}
+
+// Print the instantiation subtree rooted at `name`, descending through
+// `children` (def name -> names of instantiated modules). Stops and marks a
+// cycle rather than recursing forever when a module (directly or
+// transitively) instantiates itself.
+fn print_hierarchy_tree(
+	name: &str,
+	children: &HashMap<&str, Vec<&str>>,
+	visiting: &mut HashSet<String>,
+	indent: &str
+) {
+	let insts = match children.get(name) {
+		Some(insts) if !insts.is_empty() => insts,
+		_ => return
+	};
+
+	if !visiting.insert(name.to_string()) {
+		println!("{}insts: []  # cycle detected at {}", indent, escape_str(name));
+		return;
+	}
+
+	println!("{}insts:", indent);
+	let item_indent = format!("{}  ", indent);
+	let child_indent = format!("{}  ", item_indent);
+	for inst in insts.iter() {
+		println!("{}- mod_name: {}", item_indent, escape_str(inst));
+		print_hierarchy_tree(inst, children, visiting, &child_indent);
+	}
+
+	visiting.remove(name);
+}
+
+fn run_hierarchy(
+	opt: &Opt
+) -> i32 {
+
+    // read in define variables
+    let mut defines = HashMap::new();
+    for define in &opt.defines {
+		let mut define = define.splitn(2, '=');
+        let ident = String::from(define.next().unwrap());
+        let text = if let Some(x) = define.next() {
+            let x = enquote::unescape(x, None).unwrap();
+            Some(DefineText::new(x, None))
+        } else {
+            None
+        };
+        let define = Define::new(ident.clone(), vec![], text);
+        defines.insert(ident, Some(define));
+	}
+
+    let mut exit_code = 0;
+    let mut origin_lines: HashMap<PathBuf, LineIndex> = HashMap::new();
+    let mut defs: Vec<ModuleDef> = Vec::new();
+
+    // parse all files, accumulating definitions across the whole design
+    for path in &opt.files {
+        match parse_sv(&path, &defines, &opt.includes, opt.ignore_include, opt.allow_incomplete) {
+            Ok((syntax_tree, new_defines)) => {
+				defs.extend(collect_defs(&syntax_tree, &mut origin_lines));
+				if !opt.separate {
+					defines = new_defines;
+				}
+            }
             Err(x) => {
                 match x {
                     sv_parser_error::Error::Parse(Some((origin_path, origin_pos))) => {
                         eprintln!("parse failed: {:?}", path);
-                        print_parse_error(&origin_path, &origin_pos);
+                        print_parse_error(&origin_path, origin_pos);
                     }
                     x => {
                         eprintln!("parse failed: {:?} ({})", path, x);
@@ -117,34 +571,177 @@ fn run_opt(
             }
         }
     }
-    
-    // return exit code
+
+    if exit_code != 0 {
+        return exit_code;
+    }
+
+    // only modules participate in the instantiation hierarchy; packages and
+    // interfaces are never "top modules" and never instantiate children
+    let modules: Vec<&ModuleDef> = defs.iter().filter(|def| def.kind == DefKind::Module).collect();
+
+    // index definitions by name so children can be looked up while walking the tree
+    let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
+    for def in &modules {
+        children.insert(def.name.as_str(), def.insts.iter().map(|inst| inst.mod_name.as_str()).collect());
+    }
+
+    let mut instantiated: HashSet<&str> = HashSet::new();
+    for def in &modules {
+        for inst in &def.insts {
+            instantiated.insert(inst.mod_name.as_str());
+        }
+    }
+
+    let mut top_modules: Vec<&str> = modules.iter()
+        .map(|def| def.name.as_str())
+        .filter(|name| !instantiated.contains(name))
+        .collect();
+    top_modules.sort();
+    top_modules.dedup();
+
+    // a name is unresolved only if no input defines it under any kind, so an
+    // instantiated interface is not reported as missing
+    let defined: HashSet<&str> = defs.iter().map(|def| def.name.as_str()).collect();
+    let mut unresolved_modules: Vec<&str> = instantiated.iter()
+        .filter(|name| !defined.contains(*name))
+        .cloned()
+        .collect();
+    unresolved_modules.sort();
+
+    if opt.dot {
+        println!("digraph hierarchy {{");
+        for def in &modules {
+            for inst in &def.insts {
+                println!("  {:?} -> {:?};", def.name, inst.mod_name);
+            }
+        }
+        println!("}}");
+    } else {
+        println!("top_modules:");
+        for top in &top_modules {
+            println!("  - mod_name: {}", escape_str(top));
+            let mut visiting = HashSet::new();
+            print_hierarchy_tree(top, &children, &mut visiting, "    ");
+        }
+
+        println!("unresolved_modules:");
+        for name in &unresolved_modules {
+            println!("  - mod_name: {}", escape_str(name));
+        }
+    }
+
     exit_code
 }
 
+// Reconstruct the origin table for a preprocessed file. PreprocessedText
+// only exposes a single-point lookup (`origin(pos)`), not the underlying
+// map, so this walks every byte offset of the expanded text and merges
+// consecutive offsets that resolve to the same file and a contiguous
+// source range into a single (output_range, origin) span.
+fn collect_origin_spans(
+	preprocessed: &PreprocessedText
+) -> Vec<(Range<usize>, Option<(PathBuf, usize)>)> {
+	let text = preprocessed.text();
+	let mut spans: Vec<(Range<usize>, Option<(PathBuf, usize)>)> = Vec::new();
+
+	for offset in 0..text.len() {
+		let origin = preprocessed.origin(offset).map(|(path, pos)| (path.clone(), pos));
+		let extends_last = match spans.last() {
+			Some((range, Some((last_path, last_pos)))) => {
+				range.end == offset && origin.as_ref().map_or(false, |(path, pos)| {
+					path == last_path && *pos == last_pos + (range.end - range.start)
+				})
+			}
+			Some((range, None)) => range.end == offset && origin.is_none(),
+			None => false
+		};
+		if extends_last {
+			spans.last_mut().unwrap().0.end = offset + 1;
+		} else {
+			spans.push((offset..offset + 1, origin));
+		}
+	}
+
+	spans
+}
+
+// Collect the distinct set of original source files touched while
+// preprocessing `path` for --depfile: the file itself plus every
+// `include`d file whose text actually got inlined. If preprocessing
+// fails outright, the file still depends on itself.
+fn collect_include_deps(
+	path: &PathBuf,
+	defines: &HashMap<String, Option<Define>>,
+	includes: &[PathBuf],
+	ignore_include: bool,
+	allow_incomplete: bool
+) -> BTreeSet<PathBuf> {
+	let mut deps = BTreeSet::new();
+	deps.insert(path.clone());
+	if let Ok((preprocessed, _)) = preprocess(path, defines, includes, ignore_include, allow_incomplete) {
+		for (_, origin) in collect_origin_spans(&preprocessed) {
+			if let Some((origin_path, _)) = origin {
+				deps.insert(origin_path);
+			}
+		}
+	}
+	deps
+}
+
+// Write a Make/Ninja-style depfile: one `target: prereq1 prereq2 ...` line
+// per top-level input, so incremental builds know to re-run svinst when
+// any transitively included file changes.
+fn write_depfile(depfile: &PathBuf, entries: &[(PathBuf, BTreeSet<PathBuf>)]) {
+	let mut out = String::new();
+	for (target, deps) in entries {
+		out.push_str(&target.to_string_lossy());
+		out.push(':');
+		for dep in deps {
+			out.push(' ');
+			out.push_str(&dep.to_string_lossy());
+		}
+		out.push('\n');
+	}
+	let mut f = File::create(depfile).unwrap();
+	f.write_all(out.as_bytes()).unwrap();
+}
+
 static CHAR_CR: u8 = 0x0d;
 static CHAR_LF: u8 = 0x0a;
 
+// Print a caret-pointer snippet for a parse failure. sv-parser already
+// resolves Error::Parse's offset through the preprocessor's origin table
+// before raising it, so `origin_path`/`origin_pos` are already the real
+// file and the real offset in it — including for failures inside an
+// `` `include``d file or expanded macro text — and can be rendered as-is.
 fn print_parse_error(
 	origin_path: &PathBuf,
-	origin_pos: &usize
+	origin_pos: usize
+) {
+	render_caret(origin_path, origin_pos);
+}
+
+fn render_caret(
+	origin_path: &PathBuf,
+	origin_pos: usize
 ) {
     let mut f = File::open(&origin_path).unwrap();
     let mut s = String::new();
     let _ = f.read_to_string(&mut s);
 
     let mut pos = 0;
-    let mut column = 1;
+    let mut line = 1;
     let mut last_lf = None;
     while pos < s.len() {
         if s.as_bytes()[pos] == CHAR_LF {
-            column += 1;
+            line += 1;
             last_lf = Some(pos);
         }
         pos += 1;
 
-        if *origin_pos == pos {
-            let row = if let Some(last_lf) = last_lf {
+        if origin_pos == pos {
+            let col = if let Some(last_lf) = last_lf {
                 pos - last_lf
             } else {
                 pos + 1
@@ -157,13 +754,13 @@ fn print_parse_error(
                 next_crlf += 1;
             }
 
-            let column_len = format!("{}", column).len();
+            let line_len = format!("{}", line).len();
 
-            eprint!(" {}:{}:{}\n", origin_path.to_string_lossy(), column, row);
+            eprint!(" {}:{}:{}\n", origin_path.to_string_lossy(), line, col);
 
-            eprint!("{}|\n", " ".repeat(column_len + 1));
+            eprint!("{}|\n", " ".repeat(line_len + 1));
 
-            eprint!("{} |", column);
+            eprint!("{} |", line);
 
             let beg = if let Some(last_lf) = last_lf {
                 last_lf + 1
@@ -175,7 +772,7 @@ fn print_parse_error(
                 String::from_utf8_lossy(&s.as_bytes()[beg..next_crlf])
             );
 
-            eprint!("{}|", " ".repeat(column_len + 1));
+            eprint!("{}|", " ".repeat(line_len + 1));
 
             eprint!(
                 " {}{}\n",
@@ -197,161 +794,63 @@ fn show_macro_defs(
 	}
 }
 
-fn analyze_defs(
-	syntax_tree: &SyntaxTree
-) {
-    // &SyntaxTree is iterable
-    for node in syntax_tree {
-        // The type of each node is RefNode
-        match node {
-            RefNode::ModuleDeclarationNonansi(x) => {
-                // unwrap_node! gets the nearest ModuleIdentifier from x
-				let id = match unwrap_node!(x, ModuleIdentifier) {
-					None => { continue; },
-					Some(x) => x
-				};
-				let id = match get_identifier(id) {
-					None => { continue; },
-					Some(x) => x
-				};				
-                // Original string can be got by SyntaxTree::get_str(self, node: &RefNode)
-                let id = match syntax_tree.get_str(&id) {
-					None => { continue; },
-					Some(x) => x
-				};	
-                // Declare the new module
-				println!("      - mod_name: {}", escape_str(id));
-				println!("        insts:");
-            }
-            RefNode::ModuleDeclarationAnsi(x) => {
-				let id = match unwrap_node!(x, ModuleIdentifier) {
-					None => { continue; },
-					Some(x) => x
-				};
-				let id = match get_identifier(id) {
-					None => { continue; },
-					Some(x) => x
-				};		
-                let id = match syntax_tree.get_str(&id) {
-					None => { continue; },
-					Some(x) => x
-				};	
-				println!("      - mod_name: {}", escape_str(id));
-				println!("        insts:");
-            }
-            RefNode::PackageDeclaration(x) => {
-				let id = match unwrap_node!(x, PackageIdentifier) {
-					None => { continue; },
-					Some(x) => x
-				};
-				let id = match get_identifier(id) {
-					None => { continue; },
-					Some(x) => x
-				};		
-                let id = match syntax_tree.get_str(&id) {
-					None => { continue; },
-					Some(x) => x
-				};	
-				println!("      - pkg_name: {}", escape_str(id));
-				println!("        insts:");
-            }
-            RefNode::InterfaceDeclaration(x) => {
-				let id = match unwrap_node!(x, InterfaceIdentifier) {
-					None => { continue; },
-					Some(x) => x
-				};
-				let id = match get_identifier(id) {
-					None => { continue; },
-					Some(x) => x
-				};		
-                let id = match syntax_tree.get_str(&id) {
-					None => { continue; },
-					Some(x) => x
-				};
-				println!("      - intf_name: {}", escape_str(id));
-				println!("        insts:");
-            }
-            RefNode::ModuleInstantiation(x) => {
-				// write the module name
-				let id = match unwrap_node!(x, ModuleIdentifier) {
-					None => { continue; },
-					Some(x) => x
-				};
-				let id = match get_identifier(id) {
-					None => { continue; },
-					Some(x) => x
-				};		
-                let id = match syntax_tree.get_str(&id) {
-					None => { continue; },
-					Some(x) => x
-				};
-                println!("          - mod_name: {}", escape_str(id));
-                // write the instance name
-				let id = match unwrap_node!(x, InstanceIdentifier) {
-					None => { continue; },
-					Some(x) => x
-				};
-				let id = match get_identifier(id) {
-					None => { continue; },
-					Some(x) => x
-				};		
-                let id = match syntax_tree.get_str(&id) {
-					None => { continue; },
-					Some(x) => x
-				};
-                println!("            inst_name: {}", escape_str(id));
-			}
-            RefNode::PackageImportItem(x) => {
-				// write the package name
-				let id = match unwrap_node!(x, PackageIdentifier) {
-					None => { continue; },
-					Some(x) => x
-				};
-				let id = match get_identifier(id) {
-					None => { continue; },
-					Some(x) => x
-				};		
-                let id = match syntax_tree.get_str(&id) {
-					None => { continue; },
-					Some(x) => x
-				};
-                println!("          - pkg_name: {}", escape_str(id));
-			}
-			RefNode::ImplicitClassHandleOrClassScope(x) => {
-				// write the package name
-				let id = match unwrap_node!(x, ClassIdentifier) {
-					None => { continue; },
-					Some(x) => x
-				};
-				let id = match get_identifier(id) {
-					None => { continue; },
-					Some(x) => x
-				};		
-                let id = match syntax_tree.get_str(&id) {
-					None => { continue; },
-					Some(x) => x
-				};
-                println!("          - pkg_name: {}", escape_str(id));
-			}
-			RefNode::ImplicitClassHandleOrClassScopeOrPackageScope(x) => {
-				// write the package name
-				let id = match unwrap_node!(x, ClassIdentifier) {
-					None => { continue; },
-					Some(x) => x
-				};
-				let id = match get_identifier(id) {
-					None => { continue; },
-					Some(x) => x
-				};
-                let id = match syntax_tree.get_str(&id) {
-					None => { continue; },
-					Some(x) => x
-				};
-                println!("          - pkg_name: {}", escape_str(id));
+// Tracks the byte offset of the start of each line in an origin file, so a
+// byte offset can be converted to a 1-based (line, col) pair by binary search.
+struct LineIndex {
+	line_starts: Vec<usize>
+}
+
+impl LineIndex {
+	fn new(text: &str) -> Self {
+		let mut line_starts = vec![0];
+		for (i, byte) in text.bytes().enumerate() {
+			if byte == CHAR_LF {
+				line_starts.push(i + 1);
 			}
-            _ => (),
-        }
-    }
+		}
+		LineIndex { line_starts }
+	}
+
+	fn from_file(path: &PathBuf) -> Self {
+		let mut s = String::new();
+		if let Ok(mut f) = File::open(path) {
+			let _ = f.read_to_string(&mut s);
+		}
+		LineIndex::new(&s)
+	}
+
+	fn locate(&self, offset: usize) -> (usize, usize) {
+		let line = match self.line_starts.binary_search(&offset) {
+			Ok(i) => i,
+			Err(i) => i - 1
+		};
+		(line + 1, offset - self.line_starts[line] + 1)
+	}
+}
+
+// Convert a byte offset into an origin file to a (line, col) pair, reading
+// and indexing that file at most once per cache.
+fn line_col_at(
+	origin_lines: &mut HashMap<PathBuf, LineIndex>,
+	path: &PathBuf,
+	offset: usize
+) -> (usize, usize) {
+	let index = origin_lines.entry(path.clone()).or_insert_with(|| LineIndex::from_file(path));
+	index.locate(offset)
+}
+
+// Resolve a Locate from the preprocessed syntax tree back to the original
+// source file and (line, col) it came from, reading each origin file at
+// most once per run_opt invocation.
+fn locate_origin(
+	syntax_tree: &SyntaxTree,
+	locate: &Locate,
+	origin_lines: &mut HashMap<PathBuf, LineIndex>
+) -> Option<(PathBuf, usize, usize)> {
+	let (path, offset) = syntax_tree.get_origin(locate)?;
+	let path = path.clone();
+	let (line, col) = line_col_at(origin_lines, &path, offset);
+	Some((path, line, col))
 }
 
 fn print_full_tree(
@@ -506,7 +1005,12 @@ mod tests {
 			ignore_include: false,
 			separate: false,
 			show_macro_defs: false,
-			allow_incomplete: false
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
 		};
 		expect_pass(&opt);
     }
@@ -522,7 +1026,12 @@ mod tests {
 			ignore_include: false,
 			separate: false,
 			show_macro_defs: false,
-			allow_incomplete: false
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
 		};
 		expect_fail(&opt);
     }
@@ -538,7 +1047,12 @@ mod tests {
 			ignore_include: false,
 			separate: false,
 			show_macro_defs: false,
-			allow_incomplete: false
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
 		};
 		expect_pass(&opt);
     }
@@ -555,7 +1069,12 @@ mod tests {
 			ignore_include: false,
 			separate: false,
 			show_macro_defs: true,
-			allow_incomplete: false
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
 		};
 		expect_pass(&opt);
     }
@@ -571,7 +1090,12 @@ mod tests {
 			ignore_include: false,
 			separate: false,
 			show_macro_defs: false,
-			allow_incomplete: false
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
 		};
 		expect_pass(&opt);
     }
@@ -587,7 +1111,12 @@ mod tests {
 			ignore_include: false,
 			separate: false,
 			show_macro_defs: false,
-			allow_incomplete: false
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
 		};
 		expect_pass(&opt);
     }
@@ -603,7 +1132,12 @@ mod tests {
 			ignore_include: false,
 			separate: false,
 			show_macro_defs: false,
-			allow_incomplete: false
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
 		};
 		expect_pass(&opt);
     }
@@ -619,7 +1153,12 @@ mod tests {
 			ignore_include: false,
 			separate: false,
 			show_macro_defs: false,
-			allow_incomplete: false
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
 		};
 		expect_pass(&opt);
     }
@@ -635,7 +1174,12 @@ mod tests {
 			ignore_include: false,
 			separate: false,
 			show_macro_defs: false,
-			allow_incomplete: false
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
 		};
 		expect_pass(&opt);
     }
@@ -656,8 +1200,108 @@ mod tests {
 			ignore_include: false,
 			separate: false,
 			show_macro_defs: false,
-			allow_incomplete: false
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
+		};
+		expect_pass(&opt);
+    }
+
+    #[test]
+    fn test_preprocess() {
+        let opt = Opt{
+			files: vec![PathBuf::from("testcases/pass/test.sv")],
+			defines: vec![],
+			includes: vec![],
+			full_tree: false,
+			include_whitespace: false,
+			ignore_include: false,
+			separate: false,
+			show_macro_defs: false,
+			allow_incomplete: false,
+			preprocess: true,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
+		};
+		let ret = run_preprocess(&opt);
+		assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        let opt = Opt{
+			files: vec![
+			    PathBuf::from("testcases/pass/multi/define1.v"),
+			    PathBuf::from("testcases/pass/multi/test1.sv"),
+			    PathBuf::from("testcases/pass/multi/define2.v"),
+			    PathBuf::from("testcases/pass/multi/dut.v")
+			],
+			defines: vec![],
+			includes: vec![],
+			full_tree: false,
+			include_whitespace: false,
+			ignore_include: false,
+			separate: false,
+			show_macro_defs: false,
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: true,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: None
+		};
+		let ret = run_hierarchy(&opt);
+		assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn test_format_json() {
+        let opt = Opt{
+			files: vec![PathBuf::from("testcases/pass/test.sv")],
+			defines: vec![],
+			includes: vec![],
+			full_tree: false,
+			include_whitespace: false,
+			ignore_include: false,
+			separate: false,
+			show_macro_defs: false,
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("json"),
+			depfile: None
+		};
+		expect_pass(&opt);
+    }
+
+    #[test]
+    fn test_depfile() {
+        let depfile_path = PathBuf::from("testcases/pass/test.sv.d");
+        let opt = Opt{
+			files: vec![PathBuf::from("testcases/pass/test.sv")],
+			defines: vec![],
+			includes: vec![],
+			full_tree: false,
+			include_whitespace: false,
+			ignore_include: false,
+			separate: false,
+			show_macro_defs: false,
+			allow_incomplete: false,
+			preprocess: false,
+			hierarchy: false,
+			dot: false,
+			format: String::from("yaml"),
+			depfile: Some(depfile_path.clone())
 		};
 		expect_pass(&opt);
+		let contents = std::fs::read_to_string(&depfile_path).unwrap();
+		assert!(contents.starts_with("testcases/pass/test.sv:"));
+		std::fs::remove_file(&depfile_path).unwrap();
     }
 }